@@ -2178,7 +2178,10 @@ impl Connection {
                 if let Some(tm) = self.last_test_delay {
                     self.last_test_delay = None;
                     let new_delay = tm.elapsed().as_millis() as u32;
-                    // 已移除动态 FPS 相关功能
+                    video_service::VIDEO_QOS
+                        .lock()
+                        .unwrap()
+                        .user_network_rtt(self.inner.id(), Duration::from_millis(new_delay as _));
                 }
             }
         } else if let Some(message::Union::SwitchSidesResponse(_s)) = msg.union {
@@ -2844,11 +2847,14 @@ impl Connection {
                         self.send(msg).await;
                     } 
                         // 已移除动态 FPS 相关功能
-                    Some(misc::Union::ClientRecordStatus(status)) => 
-                        video_service::VIDEO_QOS
-                            .lock()
-                            .unwrap()
-                            .user_record(self.inner.id(), status > 0);
+                    Some(misc::Union::ClientRecordStatus(status)) => {
+                        if self.is_video_qos_conn() {
+                            video_service::VIDEO_QOS
+                                .lock()
+                                .unwrap()
+                                .user_record(self.inner.id(), status > 0);
+                        }
+                    }
                     Some(misc::Union::SelectedSid(sid)) => {
                         if sid > 0 {
                             self.inner.set_selected_sid(sid);
@@ -3378,15 +3384,18 @@ impl Connection {
             } else {
                 image_quality = q.value();
             }
-            if image_quality > 0 {
+            if image_quality > 0 && self.is_video_qos_conn() {
                 video_service::VIDEO_QOS
                     .lock()
                     .unwrap()
                     .user_image_quality(self.inner.id(), image_quality);
             }
         }
-        if o.custom_fps > 0 {
-            // 已移除动态 FPS 相关功能
+        if o.custom_fps > 0 && self.is_video_qos_conn() {
+            video_service::VIDEO_QOS
+                .lock()
+                .unwrap()
+                .user_fps(self.inner.id(), o.custom_fps as _);
         }
         if let Some(q) = o.supported_decoding.clone().take() {
             scrap::codec::Encoder::update(scrap::codec::EncodingUpdate::Update(self.inner.id(), q));
@@ -3902,6 +3911,11 @@ impl Connection {
         false
     }
 
+    // VIDEO_QOS auto-opens unknown ids, so only connections closed in AuthedConnID::drop may feed it.
+    fn is_video_qos_conn(&self) -> bool {
+        self.is_authed_remote_conn() || self.is_authed_view_camera_conn()
+    }
+
     #[cfg(feature = "unix-file-copy-paste")]
     async fn handle_file_clip(&mut self, clip: clipboard::ClipboardFile) {
         let is_stopping_allowed = clip.is_stopping_allowed();
@@ -1,67 +1,262 @@
 use super::*;
 use scrap::codec::{Quality, BR_BALANCED};
-use std::time::Duration;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
 
 // 常量定义
 pub const FPS: u32 = 59;          // 默认FPS值
 pub const MIN_FPS: u32 = 59;       // 最小FPS值
 pub const MAX_FPS: u32 = 120;      // 最大FPS值
+const MAX_SPF: Duration = Duration::from_secs(1); // FPS异常时的最大帧间隔
 
 // 比特率比例常量
 const BR_MAX: f32 = 40.0;
+const RECORD_MAX_RATIO: f32 = 10.0; // 录制时的最大比例, 避免录制文件过大
+const RECORD_QUALITY_FLOOR: Quality = Quality::Balanced; // 录制时的最低画质
 const BR_MIN: f32 = 0.2;
 const BR_MIN_HIGH_RESOLUTION: f32 = 0.1;
 const MAX_BR_MULTIPLE: f32 = 1.0;
+const HIGH_RESOLUTION_PIXELS: u64 = 3840 * 2160; // 达到该像素数时使用高分辨率的最低比例
+
+// 自适应码率常量
+const SEND_COUNTER_THRESHOLD: usize = 10; // 积压帧数阈值
+const BACKLOG_MAX_STRETCH: f32 = 4.0;     // 积压时帧间隔最多延长的倍数
+const CONGESTION_ROUNDS: u32 = 3;         // 连续超过阈值多少次后降低比例
+const RATIO_STEP: f32 = 0.1;              // 每次调整的比例步长
+
+// 基于RTT的自适应FPS常量
+const RTT_LOW: Duration = Duration::from_millis(50);   // 默认低于该RTT时使用MAX_FPS
+const RTT_HIGH: Duration = Duration::from_millis(300); // 默认高于该RTT时使用MIN_FPS
+const FPS_RAMP_UP_STEP: u32 = 5;                       // RTT好转时每次最多提升的FPS
+
+// FPS平滑过渡常量
+const FPS_TRANSITION_STEP: u32 = 10;                           // 每次tick最多变化的FPS
+const FPS_TICK_INTERVAL: Duration = Duration::from_millis(100); // 两次tick的最小间隔
+
+// 比特率平滑窗口大小
+const BITRATE_WINDOW: usize = 8;
+// 编码器上报的比特率(kbps)上限, 超过时视为异常值并限制, 避免影响平均值和界面显示
+pub const MAX_BITRATE: u32 = 1_000_000;
+
+// 自适应比例调整的迟滞常量
+pub const RATIO_MIN_STEP: f32 = 0.03;                      // 小于该变化的调整被忽略
+pub const RATIO_DWELL: Duration = Duration::from_millis(1000); // 两次调整的最小间隔
+
+// 显示变化后VBR状态需要保持的时间, 之后stable_vbr_state才切换
+const VBR_DEBOUNCE: Duration = Duration::from_secs(2);
+
+// 最后一个用户离开后保留比例和FPS的时间, 期间重新连接时恢复
+const WARM_GRACE: Duration = Duration::from_secs(10);
+
+// 用户手动设置画质后, 在该时间内不自动降低比例
+const USER_OVERRIDE_WINDOW: Duration = Duration::from_secs(3);
+
+// 最多记录的显示数量, 超出时淘汰最久未更新的显示
+pub const MAX_DISPLAYS: usize = 16;
+
+// 用户请求的FPS相差超过该值时视为冲突
+const FPS_CONFLICT_THRESHOLD: u32 = 30;
+
+// 编码耗时平滑窗口大小
+const ENCODE_TIME_WINDOW: usize = 10;
+
+// 用户画质设置的默认过期时间
+const QUALITY_STALE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+// 对端控制消息中的设置, None表示保持原有设置
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RemoteQosSettings {
+    pub fps: Option<u32>,         // 请求的FPS
+    pub quality: Option<Quality>, // 选择的画质
+    pub record: Option<bool>,     // 是否在录制
+}
 
 // 用户会话数据结构
 #[derive(Default, Debug, Clone)]
 struct UserData {
     quality: Option<(i64, Quality)>, // (时间戳, 画质设置)
     record: bool,                    // 是否在录制
+    fps: Option<u32>,                // 用户请求的FPS
+    quality_seq: u64,                // 画质设置的序号, 不受时钟调整影响
+    rtt: Option<Duration>,           // 最近一次测得的网络RTT
 }
 
 // 显示数据结构
-#[derive(Default, Debug, Clone)]
+#[derive(Debug, Clone)]
 struct DisplayData {
     support_changing_quality: bool,  // 是否支持改变画质
+    backlog: usize,                  // 最近一次上报的积压帧数
+    congested_rounds: u32,           // 连续超过阈值的次数
+    width: u32,                      // 显示宽度
+    height: u32,                     // 显示高度
+    encode_times: VecDeque<Duration>, // 最近的编码耗时
+    encode_fps_cap: Option<u32>,     // 编码跟不上时的FPS上限
+    encode_overloaded: bool,         // 固定FPS下编码跟不上
+    idle_frames: usize,              // 上次上报后画面没有变化而没有发送的帧数
+    last_update_ms: i64,             // 最近一次注册, 设置或上报的时间
+    last_report_ms: i64,             // 积压统计窗口的开始时间
+}
+
+impl Default for DisplayData {
+    fn default() -> Self {
+        DisplayData {
+            support_changing_quality: true, // 默认支持
+            backlog: 0,
+            congested_rounds: 0,
+            width: 0,
+            height: 0,
+            encode_times: VecDeque::with_capacity(ENCODE_TIME_WINDOW),
+            encode_fps_cap: None,
+            encode_overloaded: false,
+            idle_frames: 0,
+            last_update_ms: 0,
+            last_report_ms: 0,
+        }
+    }
+}
+
+// 最后一个用户离开时的状态, 用于很快重新连接时恢复
+#[derive(Debug, Clone, Copy)]
+struct WarmState {
+    left_ms: i64,    // 最后一个用户离开的时间
+    ratio: f32,      // 离开时的比例
+    fps: u32,        // 离开时的当前FPS
+    target_fps: u32, // 离开时的目标FPS
 }
 
 // 视频QoS主控制器
 pub struct VideoQoS {
     fps: u32,                       // 当前FPS值
+    target_fps: u32,                // 目标FPS值, 当前FPS逐步向其靠近
+    last_tick: Option<Instant>,     // 上次平滑过渡的时间
     ratio: f32,                     // 当前比特率比例
     users: HashMap<i32, UserData>,  // 用户会话映射
     displays: HashMap<String, DisplayData>, // 显示设备映射
     bitrate_store: u32,             // 存储的比特率
+    bitrate_samples: VecDeque<u32>, // 最近的比特率样本
     fixed_fps: Option<u32>,         // 固定FPS设置
+    rtt_low: Duration,              // 低于该RTT时使用MAX_FPS
+    rtt_high: Duration,             // 高于该RTT时使用MIN_FPS
+    last_ratio_change_ms: i64,      // 上次自适应调整比例的时间
+    ratio_dwell: Duration,          // 两次自适应调整的最小间隔
+    now_ms: fn() -> i64,            // 获取当前时间(毫秒), 测试时可替换
+    user_override_until_ms: i64,    // 用户手动设置画质后的保护截止时间
+    quality_seq: u64,               // 单调递增的画质设置序号
+    quality_stale_timeout: Duration, // 用户画质设置的过期时间
+    applied_quality: Quality,       // 最近一次用于计算比例的画质
+    warm_state: Option<WarmState>,  // 最后一个用户离开时的状态
+    vbr_stable: Option<bool>,       // 最近一次确定的VBR状态, 未确定时直接使用in_vbr_state()
+    vbr_changed_ms: i64,            // in_vbr_state()最近一次变化的时间
+    bitrate_clamp_logged: bool,     // 是否已记录过比特率异常的日志
 }
 
 impl Default for VideoQoS {
     fn default() -> Self {
         VideoQoS {
             fps: FPS,
+            target_fps: FPS,
+            last_tick: None,
             ratio: BR_BALANCED,
             users: Default::default(),
             displays: Default::default(),
             bitrate_store: 0,
+            bitrate_samples: VecDeque::with_capacity(BITRATE_WINDOW),
             fixed_fps: None,
+            rtt_low: RTT_LOW,
+            rtt_high: RTT_HIGH,
+            last_ratio_change_ms: 0,
+            ratio_dwell: RATIO_DWELL,
+            now_ms: hbb_common::get_time,
+            user_override_until_ms: 0,
+            quality_seq: 0,
+            quality_stale_timeout: QUALITY_STALE_TIMEOUT,
+            applied_quality: Quality::Balanced,
+            warm_state: None,
+            vbr_stable: None,
+            vbr_changed_ms: 0,
+            bitrate_clamp_logged: false,
         }
     }
 }
 
 impl VideoQoS {
-    // 设置或取消固定FPS
+    // 根据给定的用户(id, 画质, 是否录制)和显示(名称, 是否支持改变画质)创建, 便于测试.
+    // 用户按顺序设置画质, 时间戳为当前时间, 后面的用户视为最新设置
+    #[cfg(test)]
+    fn with_state(users: &[(i32, Quality, bool)], displays: &[(&str, bool)]) -> Self {
+        let mut qos = VideoQoS::default();
+        for (name, support) in displays {
+            qos.new_display(name.to_string());
+            qos.set_support_changing_quality(name, *support);
+        }
+        // 初始的显示状态立即生效
+        qos.vbr_stable = None;
+        for (id, quality, record) in users {
+            qos.on_connection_open(*id);
+            qos.apply_remote_settings(
+                *id,
+                RemoteQosSettings {
+                    quality: Some(*quality),
+                    record: Some(*record),
+                    ..Default::default()
+                },
+            );
+        }
+        qos
+    }
+
+    // 设置或取消固定FPS, 只改变目标FPS, 由tick平滑过渡
     pub fn set_fixed_fps(&mut self, fps: Option<u32>) {
         if let Some(fps) = fps {
-            // 确保FPS在有效范围内
-            self.fixed_fps = Some(fps.clamp(MIN_FPS, MAX_FPS));
-            self.fps = self.fixed_fps.unwrap(); // 立即应用
+            // 确保FPS在有效范围内, 被改写时输出警告
+            let (clamped, rewritten) = clamp_fixed_fps(fps);
+            if rewritten {
+                log::warn!(
+                    "requested fps {} is out of range, clamped to {}",
+                    fps,
+                    clamped
+                );
+            }
+            self.fixed_fps = Some(clamped);
+            self.target_fps = clamped;
         } else {
+            // 从当前FPS开始自动调整, 避免跳回默认FPS
             self.fixed_fps = None;
-            self.fps = FPS; // 回退到默认FPS
+            self.target_fps = self.fps.clamp(MIN_FPS, MAX_FPS);
+            self.update_requested_fps();
         }
     }
-    
+
+    // 使当前FPS向目标FPS靠近, 每次最多变化FPS_TRANSITION_STEP
+    pub fn tick(&mut self, now: Instant) {
+        if let Some(last_tick) = self.last_tick {
+            // 多个显示线程同时调用时只生效一次
+            if now.saturating_duration_since(last_tick) < FPS_TICK_INTERVAL {
+                return;
+            }
+        }
+        self.last_tick = Some(now);
+        let fps = if self.fps < self.target_fps {
+            (self.fps + FPS_TRANSITION_STEP).min(self.target_fps)
+        } else {
+            self.fps
+                .saturating_sub(FPS_TRANSITION_STEP)
+                .max(self.target_fps)
+        };
+        self.fps = fps;
+        // 画质设置过期后重新计算比例
+        if self.negotiated_quality() != self.applied_quality {
+            self.renegotiate_ratio();
+        }
+    }
+
+    // 设置用户画质设置的过期时间, None表示使用默认值
+    pub fn set_quality_stale_timeout(&mut self, timeout: Option<Duration>) {
+        self.quality_stale_timeout = timeout.unwrap_or(QUALITY_STALE_TIMEOUT);
+    }
+
     // 获取当前固定FPS状态
     pub fn fixed_fps(&self) -> Option<u32> {
         self.fixed_fps
@@ -69,130 +264,1863 @@ impl VideoQoS {
 
     // 计算每帧时间
     pub fn spf(&self) -> Duration {
-        Duration::from_secs_f32(1.0 / (self.fps() as f32))
+        Duration::from_nanos(self.frame_interval_nanos())
+    }
+
+    // 计算每帧时间(纳秒), 用于高精度定时器
+    pub fn frame_interval_nanos(&self) -> u64 {
+        frame_interval_nanos_from_fps(self.fps())
     }
 
-    // 获取当前FPS
+    // 计算指定显示的每帧时间, 使用该显示的FPS, 等待客户端接收而积压的帧数超过阈值时按积压帧数暂时延长间隔,
+    // 正常发送或画面静止时不延长. 未知的显示使用全局spf()
+    pub fn spf_for_display(&self, video_service_name: &str) -> Duration {
+        let Some(display) = self.displays.get(&*display_key(video_service_name)) else {
+            return self.spf();
+        };
+        let spf = spf_from_fps(self.fps_for_display(video_service_name));
+        if display.backlog <= SEND_COUNTER_THRESHOLD {
+            return spf;
+        }
+        let stretch =
+            (display.backlog as f32 / SEND_COUNTER_THRESHOLD as f32).min(BACKLOG_MAX_STRETCH);
+        spf.mul_f32(stretch).min(MAX_SPF)
+    }
+
+    // 获取当前FPS, 至少为1
     pub fn fps(&self) -> u32 {
-        // 优先使用固定FPS
-        if let Some(fixed_fps) = self.fixed_fps {
-            return fixed_fps;
+        self.fps.max(1)
+    }
+
+    // 设置RTT区间, 低于low时使用MAX_FPS, 高于high时使用MIN_FPS, None表示使用默认值.
+    // 区间无效时使用默认区间
+    pub fn set_rtt_band(&mut self, low: Option<Duration>, high: Option<Duration>) {
+        let low = low.unwrap_or(RTT_LOW);
+        let high = high.unwrap_or(RTT_HIGH);
+        if low < high {
+            self.rtt_low = low;
+            self.rtt_high = high;
+        } else {
+            log::warn!("invalid rtt band {:?}..{:?}, use default", low, high);
+            self.rtt_low = RTT_LOW;
+            self.rtt_high = RTT_HIGH;
+        }
+    }
+
+    // 记录用户连接测得的网络RTT, 按所有连接中最差的RTT调整FPS. 会话尚未打开时忽略
+    pub fn user_network_rtt(&mut self, id: i32, rtt: Duration) {
+        let Some(user) = self.users.get_mut(&id) else {
+            return;
+        };
+        user.rtt = Some(rtt);
+        if let Some(rtt) = self.worst_rtt() {
+            self.update_network_rtt(rtt);
+        }
+    }
+
+    // 所有用户连接中最差的RTT
+    fn worst_rtt(&self) -> Option<Duration> {
+        self.users.values().filter_map(|u| u.rtt).max()
+    }
+
+    // RTT对应的FPS, RTT在区间内时FPS从MAX_FPS线性降到MIN_FPS
+    fn rtt_fps(&self, rtt: Duration) -> u32 {
+        if rtt <= self.rtt_low {
+            MAX_FPS
+        } else if rtt >= self.rtt_high {
+            MIN_FPS
+        } else {
+            let t =
+                (rtt - self.rtt_low).as_secs_f32() / (self.rtt_high - self.rtt_low).as_secs_f32();
+            MAX_FPS - ((MAX_FPS - MIN_FPS) as f32 * t).round() as u32
         }
-        self.fps
     }
 
-    // 存储比特率
+    // 根据网络RTT调整自动模式下的FPS
+    fn update_network_rtt(&mut self, rtt: Duration) {
+        // 固定FPS优先
+        if self.fixed_fps.is_some() {
+            return;
+        }
+        let target = self.rtt_fps(rtt);
+        // 不超过用户请求的FPS
+        let target = self.requested_fps().map_or(target, |fps| target.min(fps));
+        let fps = if target < self.fps {
+            // 延迟升高时立即降低
+            target
+        } else {
+            // 延迟好转时逐步回升
+            (self.fps + FPS_RAMP_UP_STEP).min(target)
+        };
+        self.fps = fps;
+        self.target_fps = self.fps;
+    }
+
+    // 存储比特率, 超过MAX_BITRATE的异常值被限制
     pub fn store_bitrate(&mut self, bitrate: u32) {
+        let bitrate = self.sane_bitrate(bitrate);
         self.bitrate_store = bitrate;
+        if self.bitrate_samples.len() >= BITRATE_WINDOW {
+            self.bitrate_samples.pop_front();
+        }
+        self.bitrate_samples.push_back(bitrate);
     }
 
-    // 获取比特率
+    // 将比特率限制在MAX_BITRATE以内, 第一次限制时记录日志
+    fn sane_bitrate(&mut self, bitrate: u32) -> u32 {
+        if bitrate <= MAX_BITRATE {
+            return bitrate;
+        }
+        if !self.bitrate_clamp_logged {
+            self.bitrate_clamp_logged = true;
+            log::warn!("bitrate {} exceeds {}, clamped", bitrate, MAX_BITRATE);
+        }
+        MAX_BITRATE
+    }
+
+    // 获取最近一次存储的比特率
     pub fn bitrate(&self) -> u32 {
         self.bitrate_store
     }
 
+    // 获取最近样本的平均比特率, 每个样本是最近一次存储时该显示编码器的比特率, 不是所有显示的总和
+    pub fn average_bitrate(&self) -> u32 {
+        if self.bitrate_samples.is_empty() {
+            return self.bitrate_store;
+        }
+        let sum = self
+            .bitrate_samples
+            .iter()
+            .fold(0u64, |sum, b| sum.saturating_add(*b as u64));
+        (sum / self.bitrate_samples.len() as u64) as u32
+    }
+
     // 获取比特率比例
-    pub fn ratio(&mut self) -> f32 {
-        if self.ratio < BR_MIN_HIGH_RESOLUTION || self.ratio > BR_MAX {
+    pub fn ratio(&self) -> f32 {
+        self.ratio
+    }
+
+    // 设置比特率比例, 超出有效范围时回退到默认值
+    pub fn set_ratio(&mut self, ratio: f32) {
+        // 没有用户时保持BR_BALANCED, 见reset_user_state
+        if self.users.is_empty() {
             self.ratio = BR_BALANCED;
+            return;
+        }
+        if (self.min_ratio()..=BR_MAX).contains(&ratio) {
+            self.ratio = ratio;
+        } else {
+            self.ratio = BR_BALANCED;
+        }
+        // 录制时不低于录制最低画质, 不高于录制最大比例
+        if self.record() {
+            self.ratio = self
+                .ratio
+                .max(RECORD_QUALITY_FLOOR.ratio())
+                .min(RECORD_MAX_RATIO);
         }
-        self.ratio
+    }
+
+    // 自适应逻辑调整比例, 变化足够大且距上次调整足够久才生效, 返回是否生效
+    fn propose_ratio(&mut self, ratio: f32) -> bool {
+        // 没有用户时保持BR_BALANCED, 见reset_user_state
+        if self.users.is_empty() {
+            return false;
+        }
+        if ratio == self.ratio {
+            return false;
+        }
+        // 到达目标或下限时允许小步调整
+        let boundary = ratio == self.negotiated_quality().ratio() || ratio == self.min_ratio();
+        if (ratio - self.ratio).abs() < RATIO_MIN_STEP && !boundary {
+            return false;
+        }
+        let now = self.now();
+        if now - self.last_ratio_change_ms < self.ratio_dwell.as_millis() as i64 {
+            return false;
+        }
+        // 用户刚手动设置画质时只允许升高
+        if ratio < self.ratio && now < self.user_override_until_ms {
+            return false;
+        }
+        self.set_ratio(ratio);
+        self.last_ratio_change_ms = now;
+        true
+    }
+
+    // 替换时间来源, 用于确定性的测试
+    #[cfg(test)]
+    fn set_clock(&mut self, now_ms: fn() -> i64) {
+        self.now_ms = now_ms;
+    }
+
+    // 当前时间(毫秒)
+    fn now(&self) -> i64 {
+        (self.now_ms)()
     }
 
     // 检查是否有用户正在录制
     pub fn record(&self) -> bool {
-        self.users.iter().any(|u| u.1.record)
+        self.recording_count() > 0
+    }
+
+    // 正在录制的用户数量
+    pub fn recording_count(&self) -> usize {
+        self.users.values().filter(|u| u.record).count()
+    }
+
+    // 获取指定显示的FPS, 自动模式下不超过该显示的编码能力
+    pub fn fps_for_display(&self, video_service_name: &str) -> u32 {
+        let Some(display) = self.displays.get(&*display_key(video_service_name)) else {
+            return self.fps();
+        };
+        match display.encode_fps_cap {
+            Some(cap) if self.fixed_fps.is_none() => self.fps().min(cap),
+            _ => self.fps(),
+        }
     }
 
-    // 设置是否支持改变画质
+    // 上报编码耗时, 平均耗时超过帧间隔时降低该显示的自动FPS
+    pub fn report_encode_time(&mut self, video_service_name: &str, encode_duration: Duration) {
+        // 与编码能力比较, 不包括积压时延长的间隔
+        let spf = spf_from_fps(self.fps_for_display(video_service_name));
+        let fixed = self.fixed_fps.is_some();
+        let Some(display) = self.displays.get_mut(&*display_key(video_service_name)) else {
+            return;
+        };
+        if display.encode_times.len() >= ENCODE_TIME_WINDOW {
+            display.encode_times.pop_front();
+        }
+        display.encode_times.push_back(encode_duration);
+        // 饱和求和, 异常的超大耗时不会溢出
+        let total = display
+            .encode_times
+            .iter()
+            .fold(Duration::ZERO, |sum, d| sum.saturating_add(*d));
+        let avg = total / display.encode_times.len() as u32;
+
+        // 固定FPS不调整, 只提示硬件无法达到
+        if fixed {
+            let overloaded = avg > spf;
+            if overloaded && !display.encode_overloaded {
+                log::warn!(
+                    "{} encode time {:?} exceeds frame interval {:?}, fixed fps can't be sustained",
+                    video_service_name,
+                    avg,
+                    spf
+                );
+            }
+            display.encode_overloaded = overloaded;
+            return;
+        }
+        display.encode_overloaded = false;
+        if avg > spf {
+            let cap = ((1.0 / avg.as_secs_f32()) as u32).clamp(MIN_FPS, MAX_FPS);
+            display.encode_fps_cap = Some(display.encode_fps_cap.map_or(cap, |c| c.min(cap)));
+        } else if let Some(cap) = display.encode_fps_cap {
+            // 编码能跟上更高的FPS时逐步放开
+            let next = cap + FPS_RAMP_UP_STEP;
+            if avg < spf_from_fps(next) {
+                display.encode_fps_cap = if next >= MAX_FPS { None } else { Some(next) };
+            }
+        }
+    }
+
+    // 设置是否支持改变画质, 显示尚未添加时自动添加
     pub fn set_support_changing_quality(&mut self, video_service_name: &str, support: bool) {
-        if let Some(display) = self.displays.get_mut(video_service_name) {
-            display.support_changing_quality = support;
+        let vbr = self.vbr_snapshot();
+        self.touch_display(video_service_name)
+            .support_changing_quality = support;
+        self.note_vbr_change(vbr);
+    }
+
+    // 获取最低比特率比例, 有高分辨率显示时允许更低的比例
+    pub fn min_ratio(&self) -> f32 {
+        let high_resolution = self
+            .displays
+            .values()
+            .any(|d| d.width as u64 * d.height as u64 >= HIGH_RESOLUTION_PIXELS);
+        if high_resolution {
+            BR_MIN_HIGH_RESOLUTION
+        } else {
+            BR_MIN
         }
     }
 
-    // 检查是否启用VBR
+    // 检查是否启用VBR, 没有显示时不启用
     pub fn in_vbr_state(&self) -> bool {
-        // 简化的VBR状态检查
-        self.displays.iter().all(|e| e.1.support_changing_quality)
+        !self.displays.is_empty() && self.displays.values().all(|d| d.support_changing_quality)
+    }
+
+    // 去抖后的VBR状态, in_vbr_state()变化后保持VBR_DEBOUNCE才切换, 避免显示频繁插拔时反复切换码率控制模式.
+    // 只在有显示时去抖, 没有显示时添加显示立即生效, 移除最后一个显示后立即不启用
+    pub fn stable_vbr_state(&self) -> bool {
+        let vbr = self.in_vbr_state();
+        match self.vbr_stable {
+            Some(stable) if stable != vbr && !self.displays.is_empty() => {
+                if self.now() - self.vbr_changed_ms >= VBR_DEBOUNCE.as_millis() as i64 {
+                    vbr
+                } else {
+                    stable
+                }
+            }
+            _ => vbr,
+        }
+    }
+
+    // 显示变化前的(VBR状态, 去抖后的VBR状态, 是否有显示)
+    fn vbr_snapshot(&self) -> (bool, bool, bool) {
+        (
+            self.in_vbr_state(),
+            self.stable_vbr_state(),
+            !self.displays.is_empty(),
+        )
+    }
+
+    // 显示变化后记录VBR状态的变化时间
+    fn note_vbr_change(&mut self, (vbr, stable, has_displays): (bool, bool, bool)) {
+        let now_vbr = self.in_vbr_state();
+        if now_vbr == vbr {
+            return;
+        }
+        if has_displays {
+            self.vbr_stable = Some(stable);
+            self.vbr_changed_ms = self.now();
+        } else {
+            // 第一个显示的状态立即生效
+            self.vbr_stable = Some(now_vbr);
+        }
     }
 }
 
 // 用户会话管理
 impl VideoQoS {
-    // 初始化新用户会话
+    // 初始化新用户会话, 会话已因提前收到设置而自动打开时保留已有的设置
     pub fn on_connection_open(&mut self, id: i32) {
+        if self.users.contains_key(&id) {
+            return;
+        }
+        let first = self.users.is_empty();
         self.users.insert(id, UserData::default());
+        // 第一个用户连接时恢复刚离开时的状态
+        if first {
+            self.resume_warm_state();
+        }
     }
 
-    // 清理用户会话
-    pub fn on_connection_close(&mut self, id: i32) {
-        self.users.remove(&id);
+    // 最后一个用户离开不超过WARM_GRACE时恢复离开时的比例和FPS, 返回是否恢复
+    fn resume_warm_state(&mut self) -> bool {
+        let Some(warm) = self.warm_state.take() else {
+            return false;
+        };
+        if self.now() - warm.left_ms > WARM_GRACE.as_millis() as i64 {
+            return false;
+        }
+        if self.fixed_fps.is_none() {
+            self.target_fps = warm.target_fps;
+            self.fps = warm.fps;
+        }
+        self.set_ratio(warm.ratio);
+        true
+    }
+
+    // 清理用户会话, 返回该用户是否在录制, 以便上层结束录制文件
+    pub fn on_connection_close(&mut self, id: i32) -> bool {
+        let user = self.users.remove(&id).unwrap_or_default();
+        let recording = user.record;
         if self.users.is_empty() {
-            *self = Default::default();
+            self.reset_user_state();
+        } else {
+            // 最差的连接可能已经离开
+            if let Some(rtt) = self.worst_rtt() {
+                self.update_network_rtt(rtt);
+            }
+            self.update_requested_fps();
+            if user.fps.is_some() && self.requested_fps().is_none() && self.fixed_fps.is_none() {
+                // 最后一个请求FPS的用户离开, 恢复自动模式的FPS
+                self.target_fps = self.worst_rtt().map_or(FPS, |rtt| self.rtt_fps(rtt));
+            }
+            if recording {
+                // 录制最低画质可能不再适用
+                self.renegotiate_ratio();
+            }
         }
+        recording
+    }
+
+    // 最后一个用户离开时重置状态, 保留显示注册, 固定FPS和其他配置.
+    // 没有用户时ratio()为BR_BALANCED, latest_quality()为Balanced, 自适应逻辑也不再改变比例
+    fn reset_user_state(&mut self) {
+        let fps = self.fixed_fps.unwrap_or(FPS);
+        let warm_state = WarmState {
+            left_ms: self.now(),
+            ratio: self.ratio,
+            fps: self.fps,
+            target_fps: self.target_fps,
+        };
+        *self = VideoQoS {
+            fps,
+            target_fps: fps,
+            displays: std::mem::take(&mut self.displays),
+            fixed_fps: self.fixed_fps,
+            rtt_low: self.rtt_low,
+            rtt_high: self.rtt_high,
+            ratio_dwell: self.ratio_dwell,
+            now_ms: self.now_ms,
+            quality_seq: self.quality_seq,
+            quality_stale_timeout: self.quality_stale_timeout,
+            warm_state: Some(warm_state),
+            bitrate_clamp_logged: self.bitrate_clamp_logged,
+            ..Default::default()
+        };
+        debug_assert_eq!(self.ratio, BR_BALANCED);
     }
 
     // 用户设置画质
     pub fn user_image_quality(&mut self, id: i32, image_quality: i32) {
-        let convert_quality = |q: i32| -> Quality {
-            match q {
-                _ if q == ImageQuality::Balanced.value() => Quality::Balanced,
-                _ if q == ImageQuality::Low.value() => Quality::Low,
-                _ if q == ImageQuality::Best.value() => Quality::Best,
-                _ => {
-                    let b = ((q >> 8 & 0xFFF) * 2) as f32 / 100.0;
-                    Quality::Custom(b.clamp(BR_MIN, BR_MAX))
-                }
-            }
-        };
+        let quality = image_quality_to_quality(image_quality);
+        self.set_user_intent(id, Some(quality), None);
+    }
 
-        let quality = Some((hbb_common::get_time(), convert_quality(image_quality)));
-        if let Some(user) = self.users.get_mut(&id) {
-            user.quality = quality;
+    // 同时设置用户的画质和FPS, None表示保持原有设置, 协商时分别考虑所有用户的画质和FPS
+    pub fn set_user_intent(&mut self, id: i32, quality: Option<Quality>, fps: Option<u32>) {
+        self.apply_remote_settings(
+            id,
+            RemoteQosSettings {
+                fps,
+                quality,
+                record: None,
+            },
+        );
+    }
+
+    // 一次应用对端控制消息中的FPS, 画质和录制状态, 所有设置更新后才重新协商一次比例
+    pub fn apply_remote_settings(&mut self, id: i32, settings: RemoteQosSettings) {
+        let RemoteQosSettings {
+            fps,
+            quality,
+            record,
+        } = settings;
+        let now = self.now();
+        if quality.is_some() {
+            self.quality_seq += 1;
+        }
+        let seq = self.quality_seq;
+        // 设置可能早于on_connection_open到达, 此时自动打开会话并应用设置, 由on_connection_close清理
+        self.on_connection_open(id);
+        let user = self.users.entry(id).or_default();
+        if let Some(quality) = quality {
+            let quality = match quality {
+                // 不是有限值时回退到平衡画质
+                Quality::Custom(ratio) => custom_quality(ratio),
+                _ => quality,
+            };
+            user.quality = Some((now, quality));
+            user.quality_seq = seq;
+        }
+        if let Some(fps) = fps {
+            user.fps = Some(fps);
+        }
+        if let Some(record) = record {
+            user.record = record;
+        }
+        if quality.is_some() {
+            self.user_override_until_ms = now + USER_OVERRIDE_WINDOW.as_millis() as i64;
+        }
+        if quality.is_some() || record.is_some() {
             // 直接更新比例
-            self.ratio = self.latest_quality().ratio();
+            self.renegotiate_ratio();
+        }
+        if fps.is_some() {
+            self.update_requested_fps();
+            if let Some((min, max)) = self.fps_conflict() {
+                log::info!("conflicting fps requests: min {}, max {}", min, max);
+            }
+        }
+    }
+
+    // 用户请求的最小和最大FPS, 相差超过FPS_CONFLICT_THRESHOLD时返回, 便于通知请求较高FPS的用户.
+    // 只用于提示, 不影响协商的FPS
+    pub fn fps_conflict(&self) -> Option<(u32, u32)> {
+        let fps = self.users.values().filter_map(|u| u.fps);
+        let min = fps.clone().min()?;
+        let max = fps.max()?;
+        (max - min > FPS_CONFLICT_THRESHOLD).then_some((min, max))
+    }
+
+    // 用户请求FPS, 自动模式下使用所有用户请求的最小值
+    pub fn user_fps(&mut self, id: i32, fps: u32) {
+        self.set_user_intent(id, None, Some(fps));
+    }
+
+    // 所有用户请求的最小FPS, 避免超出最弱连接的承受能力
+    fn requested_fps(&self) -> Option<u32> {
+        self.users
+            .values()
+            .filter_map(|u| u.fps)
+            .min()
+            .map(|fps| fps.clamp(MIN_FPS, MAX_FPS))
+    }
+
+    // 自动模式下根据用户请求更新目标FPS
+    fn update_requested_fps(&mut self) {
+        if self.fixed_fps.is_some() {
+            return;
+        }
+        if let Some(fps) = self.requested_fps() {
+            self.target_fps = fps;
         }
     }
 
     // 用户录制状态
     pub fn user_record(&mut self, id: i32, v: bool) {
-        if let Some(user) = self.users.get_mut(&id) {
-            user.record = v;
+        self.apply_remote_settings(
+            id,
+            RemoteQosSettings {
+                record: Some(v),
+                ..Default::default()
+            },
+        );
+    }
+
+    // 计算用于比特率比例的画质, 录制时不低于录制最低画质
+    fn negotiated_quality(&self) -> Quality {
+        let quality = self.latest_quality();
+        if self.record_floor_applied(&quality) {
+            RECORD_QUALITY_FLOOR
+        } else {
+            quality
         }
     }
+
+    // 按协商的画质重新计算比例, 并记录该画质以便在设置过期时发现变化
+    fn renegotiate_ratio(&mut self) {
+        let quality = self.negotiated_quality();
+        self.applied_quality = quality;
+        self.set_ratio(quality.ratio());
+    }
+
+    // 录制最低画质是否高于给定画质
+    fn record_floor_applied(&self, quality: &Quality) -> bool {
+        self.record() && cmp_quality(quality, &RECORD_QUALITY_FLOOR).is_lt()
+    }
 }
 
 // 显示管理
 impl VideoQoS {
-    // 添加新显示
+    // 添加新显示, 已存在时保留原有数据, 名称按display_key规范化
     pub fn new_display(&mut self, video_service_name: String) {
-        self.displays.insert(
-            video_service_name, 
-            DisplayData {
-                support_changing_quality: true, // 默认支持
+        let vbr = self.vbr_snapshot();
+        let now = self.now();
+        // 每次注册都是新的video_service循环, 重新开始积压统计
+        let display = self.touch_display(&video_service_name);
+        display.last_report_ms = now;
+        display.idle_frames = 0;
+        self.note_vbr_change(vbr);
+    }
+
+    // 获取显示数据并刷新更新时间, 显示不存在时添加, 超过MAX_DISPLAYS时先淘汰最久未更新的显示
+    fn touch_display(&mut self, video_service_name: &str) -> &mut DisplayData {
+        let key = display_key(video_service_name).into_owned();
+        if !self.displays.contains_key(&key) && self.displays.len() >= MAX_DISPLAYS {
+            // 淘汰最久未更新的显示, 避免异常客户端使显示数量无限增长
+            let lru = self
+                .displays
+                .iter()
+                .min_by(|a, b| {
+                    a.1.last_update_ms
+                        .cmp(&b.1.last_update_ms)
+                        .then(a.0.cmp(b.0))
+                })
+                .map(|(name, _)| name.clone());
+            if let Some(lru) = lru {
+                log::warn!("too many displays, evict {}", lru);
+                self.displays.remove(&lru);
             }
-        );
+        }
+        let now = self.now();
+        let display = self.displays.entry(key).or_insert_with(|| DisplayData {
+            last_report_ms: now,
+            ..Default::default()
+        });
+        display.last_update_ms = now;
+        display
+    }
+
+    // 设置显示分辨率, 显示尚未添加时自动添加
+    pub fn set_display_resolution(&mut self, video_service_name: &str, w: u32, h: u32) {
+        let vbr = self.vbr_snapshot();
+        let display = self.touch_display(video_service_name);
+        display.width = w;
+        display.height = h;
+        self.note_vbr_change(vbr);
     }
 
     // 移除显示
     pub fn remove_display(&mut self, video_service_name: &str) {
-        self.displays.remove(video_service_name);
+        let vbr = self.vbr_snapshot();
+        self.displays.remove(&*display_key(video_service_name));
+        self.note_vbr_change(vbr);
+    }
+
+    // 上报画面没有变化而没有发送的帧数, 在下次update_display_data时计入
+    pub fn report_idle_frames(&mut self, video_service_name: &str, idle_counter: usize) {
+        if let Some(display) = self.displays.get_mut(&*display_key(video_service_name)) {
+            display.idle_frames = display.idle_frames.saturating_add(idle_counter);
+        }
     }
 
-    // 更新显示数据 (简化版本)
-    pub fn update_display_data(&mut self, _video_service_name: &str, _send_counter: usize) {
-        // 在固定FPS模式下不需要特殊处理
-        // 保留函数签名以保持兼容性
+    // 更新显示数据, 根据积压帧数自适应调整比特率比例.
+    // send_counter为上次上报后发送的帧数, 与report_idle_frames上报的帧数之和少于按帧间隔应有的帧数时,
+    // 差值就是等待客户端接收而积压的帧数
+    pub fn update_display_data(&mut self, video_service_name: &str, send_counter: usize) {
+        let now = self.now();
+        // 上次上报后video_service使用的帧间隔
+        let spf = self.spf_for_display(video_service_name);
+        let Some(display) = self.displays.get_mut(&*display_key(video_service_name)) else {
+            return;
+        };
+        // 时钟回拨时不视为积压
+        let elapsed = Duration::from_millis((now - display.last_report_ms).max(0) as u64);
+        display.last_report_ms = now;
+        display.last_update_ms = now;
+        let expected = (elapsed.as_nanos() / spf.as_nanos().max(1)) as usize;
+        let handled = send_counter.saturating_add(std::mem::take(&mut display.idle_frames));
+        let backlog = expected.saturating_sub(handled);
+        display.backlog = backlog;
+        if backlog > SEND_COUNTER_THRESHOLD {
+            display.congested_rounds = display.congested_rounds.saturating_add(1);
+        } else {
+            display.congested_rounds = 0;
+        }
+        // 只有VBR时才调整比例, 否则改变比例会导致重建编码器
+        if !self.in_vbr_state() {
+            return;
+        }
+
+        // 每个显示单独判断, 只有所有显示都恢复后才回升
+        let congested = self
+            .displays
+            .values()
+            .any(|d| d.congested_rounds >= CONGESTION_ROUNDS);
+        let drained = self
+            .displays
+            .values()
+            .all(|d| d.backlog <= SEND_COUNTER_THRESHOLD);
+        if congested {
+            self.propose_ratio((self.ratio - RATIO_STEP).max(self.min_ratio()));
+        } else if drained {
+            let target = self.negotiated_quality().ratio();
+            if self.ratio < target {
+                self.propose_ratio((self.ratio + RATIO_STEP).min(target));
+            }
+        }
     }
 
-    // 获取最新画质设置
+    // 获取最新的未过期画质设置, 所有设置都已过期时使用平衡画质.
+    // 设置过期后由tick重新计算比例
     pub fn latest_quality(&self) -> Quality {
+        let now = self.now();
+        let timeout = self.quality_stale_timeout.as_millis() as i64;
         self.users
-            .iter()
-            .filter_map(|(_, u)| u.quality)
-            .max_by_key(|(timestamp, _)| *timestamp)
-            .map(|(_, quality)| quality)
+            .values()
+            .filter_map(|u| {
+                u.quality
+                    .map(|(timestamp, quality)| (timestamp, quality, u.quality_seq))
+            })
+            .filter(|(timestamp, _, _)| now - *timestamp <= timeout)
+            // 以设置序号为准, 时钟回拨时仍选择真正最新的设置, 序号相同再比较时间戳
+            .max_by_key(|(timestamp, _, seq)| (*seq, *timestamp))
+            .map(|(_, quality, _)| quality)
             .unwrap_or(Quality::Balanced)
     }
 }
+
+// 显示名称的规范形式: 去掉首尾空白并转为小写, 避免重连后同一显示重复注册
+fn display_key(video_service_name: &str) -> std::borrow::Cow<'_, str> {
+    let name = video_service_name.trim();
+    if name.chars().any(char::is_uppercase) {
+        name.to_lowercase().into()
+    } else {
+        name.into()
+    }
+}
+
+// 自定义画质值的编码: 第8到19位的值乘以2即为比例的百分数, 其余位忽略
+const CUSTOM_QUALITY_SHIFT: u32 = 8;
+const CUSTOM_QUALITY_MASK: u32 = 0xFFF;
+
+// 将客户端发送的画质值转换为画质设置
+pub fn image_quality_to_quality(q: i32) -> Quality {
+    match q {
+        _ if q == ImageQuality::Balanced.value() => Quality::Balanced,
+        _ if q == ImageQuality::Low.value() => Quality::Low,
+        _ if q == ImageQuality::Best.value() => Quality::Best,
+        _ => {
+            // 先转换为无符号数, 避免负数右移
+            let v = ((q as u32) >> CUSTOM_QUALITY_SHIFT) & CUSTOM_QUALITY_MASK;
+            custom_quality(v.saturating_mul(2) as f32 / 100.0)
+        }
+    }
+}
+
+// 自定义画质, 比例限制在[BR_MIN, BR_MAX], 比例不是有限值时回退到平衡画质
+fn custom_quality(ratio: f32) -> Quality {
+    if !ratio.is_finite() {
+        return Quality::Balanced;
+    }
+    Quality::Custom(ratio.clamp(BR_MIN, BR_MAX))
+}
+
+// 将固定FPS限制在[MIN_FPS, MAX_FPS], 返回(限制后的FPS, 是否被改写)
+fn clamp_fixed_fps(fps: u32) -> (u32, bool) {
+    let clamped = fps.clamp(MIN_FPS, MAX_FPS);
+    (clamped, clamped != fps)
+}
+
+// 根据FPS计算每帧时间, FPS为0时返回最大帧间隔
+fn spf_from_fps(fps: u32) -> Duration {
+    Duration::from_nanos(frame_interval_nanos_from_fps(fps))
+}
+
+// 用整数计算帧间隔(纳秒), 避免浮点误差累积
+fn frame_interval_nanos_from_fps(fps: u32) -> u64 {
+    let max = MAX_SPF.as_nanos() as u64;
+    if fps == 0 {
+        return max;
+    }
+    (1_000_000_000 / fps as u64).min(max)
+}
+
+// 按比特率比例比较画质, 包括自定义画质
+fn cmp_quality(a: &Quality, b: &Quality) -> std::cmp::Ordering {
+    a.ratio().total_cmp(&b.ratio())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scrap::codec::BR_BEST;
+    use std::cell::Cell;
+
+    thread_local! {
+        static FAKE_NOW: Cell<i64> = Cell::new(0);
+    }
+
+    fn fake_now() -> i64 {
+        FAKE_NOW.with(|now| now.get())
+    }
+
+    fn advance_fake_now(d: Duration) {
+        FAKE_NOW.with(|now| now.set(now.get() + d.as_millis() as i64));
+    }
+
+    // 使用假时钟, 时间从1_000_000毫秒开始
+    fn with_fake_clock() -> VideoQoS {
+        FAKE_NOW.with(|now| now.set(1_000_000));
+        let mut qos = VideoQoS::default();
+        qos.set_clock(fake_now);
+        qos
+    }
+
+    fn approx_eq(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    // 不限制自适应调整间隔, 便于连续调用
+    fn without_dwell() -> VideoQoS {
+        let mut qos = with_fake_clock();
+        qos.ratio_dwell = Duration::ZERO;
+        qos
+    }
+
+    // 模拟video_service一秒后上报, 应发送的帧中有backlog帧因等待客户端接收而积压
+    fn report_backlog(qos: &mut VideoQoS, name: &str, backlog: usize) {
+        advance_fake_now(Duration::from_secs(1));
+        let elapsed =
+            Duration::from_millis((fake_now() - qos.displays[name].last_report_ms) as u64);
+        let expected = (elapsed.as_nanos() / qos.spf_for_display(name).as_nanos()) as usize;
+        qos.update_display_data(name, expected.saturating_sub(backlog));
+    }
+
+    // 反复tick直到当前FPS到达目标FPS
+    fn settle_fps(qos: &mut VideoQoS) {
+        let mut now = Instant::now();
+        while qos.fps() != qos.target_fps {
+            now += FPS_TICK_INTERVAL;
+            qos.tick(now);
+        }
+    }
+
+    #[test]
+    fn test_update_display_data_backoff_and_recover() {
+        let mut qos = without_dwell();
+        qos.on_connection_open(1);
+        qos.new_display("display0".to_owned());
+        qos.new_display("display1".to_owned());
+
+        // 按帧间隔发送了所有帧, 不是积压
+        let fps = qos.fps_for_display("display0") as usize;
+        for _ in 0..CONGESTION_ROUNDS * 2 {
+            advance_fake_now(Duration::from_secs(1));
+            qos.update_display_data("display0", fps);
+        }
+        assert!(approx_eq(qos.ratio(), BR_BALANCED));
+
+        // 画面静止时没有发送新帧, 也不是积压
+        for _ in 0..CONGESTION_ROUNDS * 2 {
+            advance_fake_now(Duration::from_secs(1));
+            qos.report_idle_frames("display0", fps);
+            qos.update_display_data("display0", 0);
+        }
+        assert!(approx_eq(qos.ratio(), BR_BALANCED));
+
+        // 未达到连续次数前不降低
+        for _ in 0..CONGESTION_ROUNDS - 1 {
+            report_backlog(&mut qos, "display0", SEND_COUNTER_THRESHOLD + 5);
+        }
+        assert!(approx_eq(qos.ratio(), BR_BALANCED));
+
+        for _ in 0..3 {
+            report_backlog(&mut qos, "display0", SEND_COUNTER_THRESHOLD + 5);
+            // 另一个显示正常上报不会打断拥塞判断
+            report_backlog(&mut qos, "display1", 0);
+        }
+        let lowered = qos.ratio();
+        assert!(lowered < BR_BALANCED);
+        assert!(lowered >= BR_MIN);
+
+        for _ in 0..20 {
+            report_backlog(&mut qos, "display0", SEND_COUNTER_THRESHOLD + 5);
+        }
+        assert!(approx_eq(qos.ratio(), BR_MIN));
+
+        for _ in 0..20 {
+            report_backlog(&mut qos, "display0", 0);
+        }
+        assert!(approx_eq(qos.ratio(), BR_BALANCED));
+
+        // 修改显示设置不缩短积压统计窗口
+        advance_fake_now(Duration::from_secs(1));
+        qos.set_display_resolution("display0", 1920, 1080);
+        qos.update_display_data("display0", 0);
+        assert_eq!(qos.displays["display0"].backlog, fps);
+    }
+
+    #[test]
+    fn test_update_display_data_requires_vbr() {
+        let mut qos = without_dwell();
+        qos.on_connection_open(1);
+        qos.new_display("display0".to_owned());
+        qos.set_support_changing_quality("display0", false);
+        for _ in 0..CONGESTION_ROUNDS * 2 {
+            report_backlog(&mut qos, "display0", SEND_COUNTER_THRESHOLD * 2);
+        }
+        // 不支持改变画质时不调整比例, 只延长帧间隔
+        assert!(approx_eq(qos.ratio(), BR_BALANCED));
+        assert!(qos.spf_for_display("display0") > spf_from_fps(qos.fps_for_display("display0")));
+    }
+
+    #[test]
+    fn test_update_network_rtt_spike_and_recovery() {
+        let mut qos = VideoQoS::default();
+        qos.update_network_rtt(Duration::from_millis(10));
+        assert_eq!(qos.fps(), FPS + FPS_RAMP_UP_STEP);
+        for _ in 0..30 {
+            qos.update_network_rtt(Duration::from_millis(10));
+        }
+        assert_eq!(qos.fps(), MAX_FPS);
+
+        // 延迟飙升立即降到MIN_FPS
+        qos.update_network_rtt(Duration::from_millis(500));
+        assert_eq!(qos.fps(), MIN_FPS);
+
+        // 区间中间值
+        qos.update_network_rtt(Duration::from_millis(175));
+        let mid = qos.fps();
+        assert!(mid > MIN_FPS && mid < MAX_FPS);
+
+        // 恢复时逐步回升
+        qos.update_network_rtt(Duration::from_millis(10));
+        assert_eq!(qos.fps(), mid + FPS_RAMP_UP_STEP);
+        for _ in 0..30 {
+            qos.update_network_rtt(Duration::from_millis(10));
+        }
+        assert_eq!(qos.fps(), MAX_FPS);
+
+        // 固定FPS优先于自适应值
+        qos.set_fixed_fps(Some(60));
+        qos.update_network_rtt(Duration::from_millis(10));
+        settle_fps(&mut qos);
+        assert_eq!(qos.fps(), 60);
+    }
+
+    #[test]
+    fn test_user_network_rtt_uses_worst_connection() {
+        let mut qos = VideoQoS::default();
+        // 会话打开前忽略
+        qos.user_network_rtt(1, Duration::from_millis(500));
+        assert_eq!(qos.fps(), FPS);
+        qos.on_connection_open(1);
+        qos.on_connection_open(2);
+        qos.user_network_rtt(1, Duration::from_millis(500));
+        assert_eq!(qos.fps(), MIN_FPS);
+        // 另一个连接的RTT较低时仍按最差的连接
+        for _ in 0..30 {
+            qos.user_network_rtt(2, Duration::from_millis(10));
+        }
+        assert_eq!(qos.fps(), MIN_FPS);
+
+        // 最差的连接离开后逐步回升
+        qos.on_connection_close(1);
+        assert_eq!(qos.fps(), MIN_FPS + FPS_RAMP_UP_STEP);
+        for _ in 0..30 {
+            qos.user_network_rtt(2, Duration::from_millis(10));
+        }
+        assert_eq!(qos.fps(), MAX_FPS);
+    }
+
+    #[test]
+    fn test_set_rtt_band() {
+        let mut qos = VideoQoS::default();
+        qos.on_connection_open(1);
+        qos.set_rtt_band(
+            Some(Duration::from_millis(200)),
+            Some(Duration::from_millis(400)),
+        );
+        for _ in 0..30 {
+            qos.user_network_rtt(1, Duration::from_millis(150));
+        }
+        assert_eq!(qos.fps(), MAX_FPS);
+        qos.user_network_rtt(1, Duration::from_millis(400));
+        assert_eq!(qos.fps(), MIN_FPS);
+
+        // 无效区间使用默认区间
+        qos.set_rtt_band(
+            Some(Duration::from_millis(400)),
+            Some(Duration::from_millis(200)),
+        );
+        assert_eq!((qos.rtt_low, qos.rtt_high), (RTT_LOW, RTT_HIGH));
+        qos.set_rtt_band(Some(Duration::from_millis(100)), None);
+        assert_eq!(
+            (qos.rtt_low, qos.rtt_high),
+            (Duration::from_millis(100), RTT_HIGH)
+        );
+        qos.user_network_rtt(1, RTT_HIGH);
+        assert_eq!(qos.fps(), MIN_FPS);
+    }
+
+    #[test]
+    fn test_set_ratio_sanitizes() {
+        let mut qos = VideoQoS::default();
+        // 没有用户时保持BR_BALANCED
+        qos.set_ratio(1.0);
+        assert!(approx_eq(qos.ratio(), BR_BALANCED));
+        qos.on_connection_open(1);
+        qos.set_ratio(1.0);
+        assert!(approx_eq(qos.ratio(), 1.0));
+        qos.set_ratio(BR_MAX + 1.0);
+        assert!(approx_eq(qos.ratio(), BR_BALANCED));
+        qos.set_ratio(BR_MIN_HIGH_RESOLUTION / 2.0);
+        assert!(approx_eq(qos.ratio(), BR_BALANCED));
+        qos.set_ratio(f32::NAN);
+        assert!(approx_eq(qos.ratio(), BR_BALANCED));
+
+        // 只读访问不需要可变借用
+        let shared = &qos;
+        assert!(approx_eq(shared.ratio(), BR_BALANCED));
+    }
+
+    #[test]
+    fn test_latest_quality_ignores_stale() {
+        let mut qos = with_fake_clock();
+        qos.on_connection_open(1);
+        qos.on_connection_open(2);
+        qos.user_image_quality(2, ImageQuality::Best.value());
+        advance_fake_now(QUALITY_STALE_TIMEOUT / 2);
+        qos.user_image_quality(1, ImageQuality::Low.value());
+        assert_eq!(qos.latest_quality(), Quality::Low);
+        // 用户2的设置已过期, 仍使用用户1的设置
+        advance_fake_now(QUALITY_STALE_TIMEOUT / 2 + Duration::from_millis(1));
+        assert_eq!(qos.latest_quality(), Quality::Low);
+
+        // 所有设置都过期时使用平衡画质
+        advance_fake_now(QUALITY_STALE_TIMEOUT / 2);
+        assert_eq!(qos.latest_quality(), Quality::Balanced);
+
+        // 过期时间可以配置
+        qos.set_quality_stale_timeout(Some(QUALITY_STALE_TIMEOUT * 2));
+        assert_eq!(qos.latest_quality(), Quality::Low);
+        qos.set_quality_stale_timeout(None);
+        assert_eq!(qos.latest_quality(), Quality::Balanced);
+    }
+
+    #[test]
+    fn test_in_vbr_state() {
+        let mut qos = VideoQoS::default();
+        assert!(!qos.in_vbr_state());
+        qos.new_display("display0".to_owned());
+        assert!(qos.in_vbr_state());
+        qos.new_display("display1".to_owned());
+        qos.set_support_changing_quality("display1", false);
+        assert!(!qos.in_vbr_state());
+        qos.remove_display("display1");
+        assert!(qos.in_vbr_state());
+    }
+
+    #[test]
+    fn test_min_ratio_by_resolution() {
+        let mut qos = without_dwell();
+        qos.on_connection_open(1);
+        qos.new_display("display0".to_owned());
+        qos.set_display_resolution("display0", 1920, 1080);
+        assert!(approx_eq(qos.min_ratio(), BR_MIN));
+        for _ in 0..50 {
+            report_backlog(&mut qos, "display0", SEND_COUNTER_THRESHOLD + 1);
+        }
+        assert!(approx_eq(qos.ratio(), BR_MIN));
+        qos.set_ratio(0.15);
+        assert!(approx_eq(qos.ratio(), BR_BALANCED));
+
+        qos.set_display_resolution("display0", 3840, 2160);
+        assert!(approx_eq(qos.min_ratio(), BR_MIN_HIGH_RESOLUTION));
+        for _ in 0..50 {
+            report_backlog(&mut qos, "display0", SEND_COUNTER_THRESHOLD + 1);
+        }
+        assert!(approx_eq(qos.ratio(), BR_MIN_HIGH_RESOLUTION));
+        qos.set_ratio(0.15);
+        assert!(approx_eq(qos.ratio(), 0.15));
+    }
+
+    #[test]
+    fn test_fps_smooth_transition() {
+        let mut qos = VideoQoS::default();
+        qos.set_fixed_fps(Some(MAX_FPS));
+        assert_eq!(qos.fps(), FPS);
+        assert_eq!(qos.target_fps, MAX_FPS);
+
+        let mut now = Instant::now();
+        qos.tick(now);
+        assert_eq!(qos.fps(), FPS + FPS_TRANSITION_STEP);
+        // 间隔内重复tick不生效
+        qos.tick(now);
+        assert_eq!(qos.fps(), FPS + FPS_TRANSITION_STEP);
+        for i in 2..=3 {
+            now += FPS_TICK_INTERVAL;
+            qos.tick(now);
+            assert_eq!(qos.fps(), FPS + i * FPS_TRANSITION_STEP);
+        }
+        settle_fps(&mut qos);
+        assert_eq!(qos.fps(), MAX_FPS);
+
+        // 取消固定FPS后从当前FPS开始, 直到自适应调整
+        qos.set_fixed_fps(None);
+        now += FPS_TICK_INTERVAL * 10;
+        qos.tick(now);
+        assert_eq!(qos.fps(), MAX_FPS);
+        qos.set_fixed_fps(Some(FPS));
+        now += FPS_TICK_INTERVAL;
+        qos.tick(now);
+        assert_eq!(qos.fps(), MAX_FPS - FPS_TRANSITION_STEP);
+    }
+
+    #[test]
+    fn test_set_support_changing_quality_before_new_display() {
+        let mut qos = VideoQoS::default();
+        qos.set_support_changing_quality("display0", false);
+        assert_eq!(qos.displays.len(), 1);
+        assert!(!qos.in_vbr_state());
+        qos.set_support_changing_quality("display0", true);
+        assert!(qos.in_vbr_state());
+        assert_eq!(qos.displays.len(), 1);
+
+        // 分辨率同样可以早于new_display设置
+        qos.set_display_resolution("display1", 3840, 2160);
+        assert_eq!(qos.displays.len(), 2);
+        assert!(approx_eq(qos.min_ratio(), BR_MIN_HIGH_RESOLUTION));
+        qos.new_display("display1".to_owned());
+        assert_eq!(qos.displays["display1"].width, 3840);
+    }
+
+    #[test]
+    fn test_spf_with_zero_fps() {
+        let qos = VideoQoS {
+            fps: 0,
+            target_fps: 0,
+            ..Default::default()
+        };
+        assert_eq!(qos.fps(), 1);
+        assert_eq!(qos.spf(), MAX_SPF);
+        assert_eq!(spf_from_fps(0), MAX_SPF);
+        assert!(spf_from_fps(MAX_FPS) < MAX_SPF);
+    }
+
+    #[test]
+    fn test_last_user_close_keeps_displays() {
+        let mut qos = VideoQoS::with_state(&[(1, Quality::Best, true)], &[("display0", false)]);
+        qos.set_fixed_fps(Some(90));
+
+        qos.on_connection_close(1);
+        assert_eq!(qos.displays.len(), 1);
+        assert!(!qos.displays["display0"].support_changing_quality);
+        assert_eq!(qos.fixed_fps(), Some(90));
+        assert_eq!(qos.fps(), 90);
+        assert!(qos.users.is_empty());
+        assert!(!qos.record());
+        assert!(approx_eq(qos.ratio(), BR_BALANCED));
+    }
+
+    #[test]
+    fn test_record_quality_floor() {
+        let mut qos = without_dwell();
+        qos.on_connection_open(1);
+        qos.new_display("display0".to_owned());
+        qos.user_image_quality(1, ImageQuality::Low.value());
+        assert!(approx_eq(qos.ratio(), Quality::Low.ratio()));
+
+        qos.user_record(1, true);
+        assert!(approx_eq(qos.ratio(), BR_BALANCED));
+        // 自适应降低也不低于录制最低画质
+        for _ in 0..50 {
+            report_backlog(&mut qos, "display0", SEND_COUNTER_THRESHOLD + 1);
+        }
+        assert!(approx_eq(qos.ratio(), BR_BALANCED));
+
+        qos.user_record(1, false);
+        assert!(approx_eq(qos.ratio(), Quality::Low.ratio()));
+    }
+
+    #[test]
+    fn test_propose_ratio_hysteresis() {
+        let mut qos = VideoQoS::default();
+        qos.on_connection_open(1);
+
+        // 微小调整被忽略
+        assert!(!qos.propose_ratio(BR_BALANCED - RATIO_MIN_STEP / 2.0));
+        assert!(approx_eq(qos.ratio(), BR_BALANCED));
+
+        assert!(qos.propose_ratio(BR_BALANCED - 0.2));
+        let lowered = qos.ratio();
+        // 间隔内的调整被忽略
+        assert!(!qos.propose_ratio(lowered - 0.2));
+        assert!(approx_eq(qos.ratio(), lowered));
+
+        qos.last_ratio_change_ms -= RATIO_DWELL.as_millis() as i64;
+        assert!(!qos.propose_ratio(lowered - RATIO_MIN_STEP / 2.0));
+        assert!(qos.propose_ratio(lowered - 0.2));
+        assert!(approx_eq(qos.ratio(), lowered - 0.2));
+    }
+
+    #[test]
+    fn test_set_fixed_fps_clamped() {
+        let mut qos = VideoQoS::default();
+        qos.set_fixed_fps(Some(200));
+        assert_eq!(qos.fixed_fps(), Some(MAX_FPS));
+        qos.set_fixed_fps(Some(1));
+        assert_eq!(qos.fixed_fps(), Some(MIN_FPS));
+        qos.set_fixed_fps(Some(90));
+        assert_eq!(qos.fixed_fps(), Some(90));
+
+        // 只有被改写时才报告
+        assert_eq!(clamp_fixed_fps(200), (MAX_FPS, true));
+        assert_eq!(clamp_fixed_fps(1), (MIN_FPS, true));
+        assert_eq!(clamp_fixed_fps(MIN_FPS), (MIN_FPS, false));
+        assert_eq!(clamp_fixed_fps(MAX_FPS), (MAX_FPS, false));
+        assert_eq!(clamp_fixed_fps(90), (90, false));
+    }
+
+    #[test]
+    fn test_image_quality_to_quality() {
+        assert_eq!(
+            image_quality_to_quality(ImageQuality::Balanced.value()),
+            Quality::Balanced
+        );
+        assert_eq!(
+            image_quality_to_quality(ImageQuality::Low.value()),
+            Quality::Low
+        );
+        assert_eq!(
+            image_quality_to_quality(ImageQuality::Best.value()),
+            Quality::Best
+        );
+        assert_eq!(image_quality_to_quality(50 << 8), Quality::Custom(1.0));
+        assert_eq!(image_quality_to_quality(150 << 8), Quality::Custom(3.0));
+        // 超出范围时限制在[BR_MIN, BR_MAX]
+        assert_eq!(image_quality_to_quality(1 << 8), Quality::Custom(BR_MIN));
+        assert_eq!(
+            image_quality_to_quality(0xFFF << 8),
+            Quality::Custom(BR_MAX)
+        );
+    }
+
+    #[test]
+    fn test_bitrate_moving_average() {
+        let mut qos = VideoQoS::default();
+        qos.on_connection_open(1);
+        assert_eq!(qos.average_bitrate(), 0);
+        for b in [1000, 2000, 3000] {
+            qos.store_bitrate(b);
+        }
+        assert_eq!(qos.average_bitrate(), 2000);
+        // bitrate()是最近一次的值
+        assert_eq!(qos.bitrate(), 3000);
+
+        // 窗口满后丢弃最旧的样本
+        for _ in 0..BITRATE_WINDOW {
+            qos.store_bitrate(4000);
+        }
+        assert_eq!(qos.average_bitrate(), 4000);
+        qos.store_bitrate(0);
+        assert_eq!(
+            qos.average_bitrate(),
+            4000 * (BITRATE_WINDOW as u32 - 1) / BITRATE_WINDOW as u32
+        );
+        assert_eq!(qos.bitrate(), 0);
+
+        qos.store_bitrate(1000);
+        qos.on_connection_close(1);
+        assert_eq!(qos.average_bitrate(), 0);
+        assert_eq!(qos.bitrate(), 0);
+    }
+
+    #[test]
+    fn test_user_fps_negotiation() {
+        let mut qos = VideoQoS::default();
+        qos.on_connection_open(1);
+        qos.on_connection_open(2);
+        qos.user_fps(1, 100);
+        settle_fps(&mut qos);
+        assert_eq!(qos.fps(), 100);
+        qos.user_fps(2, 80);
+        settle_fps(&mut qos);
+        assert_eq!(qos.fps(), 80);
+        // 限制在有效范围内
+        qos.user_fps(2, 10);
+        assert_eq!(qos.target_fps, MIN_FPS);
+        // RTT自适应不超过用户请求
+        qos.user_fps(2, 80);
+        for _ in 0..30 {
+            qos.user_network_rtt(2, Duration::from_millis(1));
+        }
+        assert_eq!(qos.fps(), 80);
+
+        qos.on_connection_close(2);
+        assert_eq!(qos.target_fps, 100);
+
+        // 最后一个请求FPS的用户离开后恢复自动模式的FPS, 没有RTT时使用默认FPS
+        qos.on_connection_open(3);
+        qos.on_connection_close(1);
+        assert_eq!(qos.target_fps, FPS);
+        // 有RTT时使用RTT对应的FPS
+        qos.on_connection_open(4);
+        qos.user_fps(3, 100);
+        qos.user_network_rtt(4, Duration::from_millis(175));
+        qos.on_connection_close(3);
+        let fps = qos.rtt_fps(Duration::from_millis(175));
+        assert!(fps > MIN_FPS && fps < MAX_FPS);
+        assert_eq!(qos.target_fps, fps);
+
+        // 固定FPS优先
+        qos.set_fixed_fps(Some(60));
+        qos.user_fps(4, 90);
+        assert_eq!(qos.target_fps, 60);
+    }
+
+    #[test]
+    fn test_new_display_keeps_existing() {
+        let mut qos = VideoQoS::default();
+        qos.new_display("display0".to_owned());
+        qos.set_support_changing_quality("display0", false);
+        qos.new_display("display0".to_owned());
+        assert_eq!(qos.displays.len(), 1);
+        assert!(!qos.displays["display0"].support_changing_quality);
+        assert!(!qos.in_vbr_state());
+    }
+
+    #[test]
+    fn test_fake_clock_stale_quality() {
+        let mut qos = with_fake_clock();
+        qos.on_connection_open(1);
+        qos.user_image_quality(1, ImageQuality::Best.value());
+        assert_eq!(qos.users[&1].quality, Some((1_000_000, Quality::Best)));
+        advance_fake_now(QUALITY_STALE_TIMEOUT);
+        assert_eq!(qos.latest_quality(), Quality::Best);
+        assert_eq!(qos.ratio(), BR_BEST);
+        // 唯一的设置过期后使用平衡画质, tick时重新计算比例
+        advance_fake_now(Duration::from_millis(1));
+        assert_eq!(qos.latest_quality(), Quality::Balanced);
+        qos.tick(Instant::now());
+        assert_eq!(qos.ratio(), BR_BALANCED);
+
+        // 重置后仍使用假时钟
+        qos.on_connection_close(1);
+        assert_eq!(qos.now(), fake_now());
+    }
+
+    #[test]
+    fn test_user_settings_before_open() {
+        let mut qos = VideoQoS::default();
+        qos.user_image_quality(1, ImageQuality::Best.value());
+        qos.user_fps(1, 90);
+        qos.user_record(2, true);
+        // 会话打开前收到的设置自动打开会话并立即生效
+        assert_eq!(qos.users.len(), 2);
+        assert_eq!(qos.latest_quality(), Quality::Best);
+        assert!(approx_eq(qos.ratio(), BR_BEST));
+        assert_eq!(qos.target_fps, 90);
+        assert!(qos.record());
+
+        // 之后打开会话保留已有的设置
+        qos.on_connection_open(1);
+        qos.on_connection_open(2);
+        assert_eq!(qos.users.len(), 2);
+        assert_eq!(qos.latest_quality(), Quality::Best);
+        assert!(approx_eq(qos.ratio(), BR_BEST));
+        assert_eq!(qos.target_fps, 90);
+        assert!(qos.record());
+
+        qos.on_connection_close(2);
+        assert!(!qos.record());
+        assert_eq!(qos.users.len(), 1);
+    }
+
+    #[test]
+    fn test_report_encode_time() {
+        let mut qos = VideoQoS {
+            fps: MAX_FPS,
+            target_fps: MAX_FPS,
+            ..Default::default()
+        };
+        qos.new_display("display0".to_owned());
+        qos.new_display("display1".to_owned());
+        for _ in 0..ENCODE_TIME_WINDOW {
+            qos.report_encode_time("display0", Duration::from_millis(12));
+        }
+        let fps = qos.fps_for_display("display0");
+        assert!(fps <= 1000 / 12);
+        assert!(qos.spf_for_display("display0") >= Duration::from_millis(12));
+        assert_eq!(qos.fps_for_display("display1"), MAX_FPS);
+
+        // 编码变快后逐步恢复
+        qos.report_encode_time("display0", Duration::from_millis(1));
+        assert!(qos.fps_for_display("display0") <= fps + FPS_RAMP_UP_STEP);
+        for _ in 0..100 {
+            qos.report_encode_time("display0", Duration::from_millis(1));
+        }
+        assert_eq!(qos.fps_for_display("display0"), MAX_FPS);
+
+        // 固定FPS不被降低
+        qos.set_fixed_fps(Some(MAX_FPS));
+        for _ in 0..ENCODE_TIME_WINDOW {
+            qos.report_encode_time("display0", Duration::from_millis(20));
+        }
+        assert_eq!(qos.fps_for_display("display0"), MAX_FPS);
+        assert!(qos.displays["display0"].encode_overloaded);
+
+        // 超大耗时不会溢出
+        qos.set_fixed_fps(None);
+        for _ in 0..ENCODE_TIME_WINDOW {
+            qos.report_encode_time("display1", Duration::MAX);
+        }
+        assert_eq!(qos.fps_for_display("display1"), MIN_FPS);
+    }
+
+    #[test]
+    fn test_image_quality_to_quality_extremes() {
+        for q in [-1, -256, i32::MIN, i32::MAX, i32::MAX - 1, 0, 1] {
+            let Quality::Custom(b) = image_quality_to_quality(q) else {
+                panic!("{} should be custom", q);
+            };
+            assert!((BR_MIN..=BR_MAX).contains(&b), "{} -> {}", q, b);
+        }
+        assert_eq!(image_quality_to_quality(-1), Quality::Custom(BR_MAX));
+        assert_eq!(image_quality_to_quality(i32::MIN), Quality::Custom(BR_MIN));
+        // 高位被忽略
+        assert_eq!(
+            image_quality_to_quality((1 << 20) | (50 << 8)),
+            Quality::Custom(1.0)
+        );
+    }
+
+    #[test]
+    fn test_user_override_window() {
+        let mut qos = with_fake_clock();
+        qos.ratio_dwell = Duration::ZERO;
+        qos.new_display("display0".to_owned());
+        qos.on_connection_open(1);
+        for _ in 0..CONGESTION_ROUNDS - 1 {
+            report_backlog(&mut qos, "display0", SEND_COUNTER_THRESHOLD + 1);
+        }
+        qos.user_image_quality(1, ImageQuality::Best.value());
+        assert_eq!(qos.ratio(), BR_BEST);
+
+        // 保护期内积压不降低比例
+        report_backlog(&mut qos, "display0", SEND_COUNTER_THRESHOLD + 1);
+        assert_eq!(qos.ratio(), BR_BEST);
+
+        advance_fake_now(USER_OVERRIDE_WINDOW);
+        report_backlog(&mut qos, "display0", SEND_COUNTER_THRESHOLD + 1);
+        let lowered = qos.ratio();
+        assert!(lowered < BR_BEST);
+
+        // 保护期内仍允许升高
+        qos.user_image_quality(1, ImageQuality::Best.value());
+        qos.set_ratio(lowered);
+        report_backlog(&mut qos, "display0", 0);
+        assert!(qos.ratio() > lowered);
+    }
+
+    #[test]
+    fn test_display_name_normalized() {
+        let mut qos = VideoQoS::default();
+        qos.new_display("display-0".to_owned());
+        qos.new_display("display-0 ".to_owned());
+        qos.new_display(" Display-0".to_owned());
+        assert_eq!(qos.displays.len(), 1);
+        assert!(qos.displays.contains_key("display-0"));
+
+        qos.set_support_changing_quality("DISPLAY-0 ", false);
+        assert!(!qos.in_vbr_state());
+        qos.remove_display("Display-0 ");
+        assert!(qos.displays.is_empty());
+    }
+
+    #[test]
+    fn test_recording_count() {
+        let mut qos = VideoQoS::default();
+        for id in 1..=3 {
+            qos.on_connection_open(id);
+        }
+        assert_eq!(qos.recording_count(), 0);
+        assert!(!qos.record());
+        qos.user_record(1, true);
+        qos.user_record(3, true);
+        assert_eq!(qos.recording_count(), 2);
+        assert!(qos.record());
+        qos.on_connection_close(3);
+        assert_eq!(qos.recording_count(), 1);
+    }
+
+    #[test]
+    fn test_on_connection_close_reports_record() {
+        let mut qos = VideoQoS::default();
+        qos.on_connection_open(1);
+        qos.on_connection_open(2);
+        qos.user_record(1, true);
+        assert!(!qos.on_connection_close(2));
+        assert!(qos.record());
+        assert!(qos.on_connection_close(1));
+        assert!(!qos.record());
+        assert!(!qos.on_connection_close(1));
+
+        // 其他用户仍在时恢复未录制的画质
+        qos.on_connection_open(1);
+        qos.on_connection_open(2);
+        qos.user_image_quality(1, ImageQuality::Low.value());
+        qos.user_record(2, true);
+        assert_eq!(qos.ratio(), BR_BALANCED);
+        assert!(qos.on_connection_close(2));
+        assert_eq!(qos.ratio(), Quality::Low.ratio());
+    }
+
+    #[test]
+    fn test_with_state() {
+        let qos = VideoQoS::with_state(
+            &[(1, Quality::Best, false), (2, Quality::Low, false)],
+            &[("display0", true), ("Display1", false)],
+        );
+        assert_eq!(qos.users.len(), 2);
+        assert_eq!(qos.displays.len(), 2);
+        assert!(!qos.displays["display1"].support_changing_quality);
+        assert!(!qos.stable_vbr_state());
+        assert_eq!(qos.latest_quality(), Quality::Low);
+        assert_eq!(qos.ratio(), Quality::Low.ratio());
+        assert!(!qos.record());
+
+        // 录制用户的最低画质
+        let qos = VideoQoS::with_state(&[(1, Quality::Low, true)], &[("display0", true)]);
+        assert!(qos.record());
+        assert!(qos.stable_vbr_state());
+        assert_eq!(qos.ratio(), BR_BALANCED);
+
+        let qos = VideoQoS::with_state(&[], &[]);
+        assert_eq!(qos.ratio(), BR_BALANCED);
+        assert_eq!(qos.latest_quality(), Quality::Balanced);
+    }
+
+    #[test]
+    fn test_set_user_intent() {
+        let mut qos = VideoQoS::default();
+        qos.on_connection_open(1);
+        qos.on_connection_open(2);
+        qos.set_user_intent(1, Some(Quality::Best), Some(90));
+        qos.set_user_intent(2, Some(Quality::Low), Some(MAX_FPS));
+        settle_fps(&mut qos);
+        assert_eq!(qos.fps(), 90);
+        assert_eq!(qos.latest_quality(), Quality::Low);
+        assert_eq!(qos.ratio(), Quality::Low.ratio());
+
+        // None保持原有设置
+        qos.set_user_intent(1, None, Some(MAX_FPS));
+        qos.set_user_intent(2, Some(Quality::Balanced), None);
+        settle_fps(&mut qos);
+        assert_eq!(qos.fps(), MAX_FPS);
+        assert_eq!(qos.latest_quality(), Quality::Balanced);
+        assert_eq!(qos.users[&1].quality.map(|(_, q)| q), Some(Quality::Best));
+
+        // 自定义画质限制在有效范围内
+        qos.set_user_intent(1, Some(Quality::Custom(1000.0)), None);
+        assert_eq!(
+            qos.users[&1].quality.map(|(_, q)| q),
+            Some(Quality::Custom(BR_MAX))
+        );
+    }
+
+    #[test]
+    fn test_record_max_ratio() {
+        let mut qos = VideoQoS::with_state(
+            &[(1, Quality::Custom(BR_MAX), false)],
+            &[("display0", true)],
+        );
+        // 未录制时不限制
+        assert_eq!(qos.ratio(), BR_MAX);
+
+        // 录制时最大比例限制极端的自定义画质, 只有一个编码通道, 录制时整个比例都受限制
+        qos.user_record(1, true);
+        assert_eq!(qos.ratio(), RECORD_MAX_RATIO);
+
+        qos.user_record(1, false);
+        assert_eq!(qos.ratio(), BR_MAX);
+    }
+
+    #[test]
+    fn test_frame_interval_nanos() {
+        let mut qos = VideoQoS::default();
+        for (fps, nanos) in [(59, 16_949_152), (60, 16_666_666), (120, 8_333_333)] {
+            qos.set_fixed_fps(Some(fps));
+            qos.fps = fps;
+            assert_eq!(qos.frame_interval_nanos(), nanos);
+            assert_eq!(qos.spf(), Duration::from_nanos(nanos));
+            // 10000帧的累计误差不超过每帧1纳秒
+            let total = qos.frame_interval_nanos() * 10_000;
+            let expected = 10_000 * 1_000_000_000 / fps as u64;
+            assert!(expected - total < 10_000);
+        }
+        assert_eq!(frame_interval_nanos_from_fps(0), MAX_SPF.as_nanos() as u64);
+    }
+
+    #[test]
+    fn test_custom_quality_non_finite() {
+        for ratio in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            assert_eq!(custom_quality(ratio), Quality::Balanced);
+        }
+    }
+
+    #[test]
+    fn test_no_users_defaults() {
+        let mut qos = without_dwell();
+        qos.new_display("display0".to_owned());
+        qos.on_connection_open(1);
+        qos.user_image_quality(1, ImageQuality::Best.value());
+        assert_eq!(qos.ratio(), BR_BEST);
+        qos.on_connection_close(1);
+        assert_eq!(qos.ratio(), BR_BALANCED);
+        assert_eq!(qos.latest_quality(), Quality::Balanced);
+        assert_eq!(qos.displays.len(), 1);
+
+        // 没有用户时自适应逻辑不改变比例
+        for _ in 0..CONGESTION_ROUNDS {
+            report_backlog(&mut qos, "display0", SEND_COUNTER_THRESHOLD + 1);
+        }
+        assert_eq!(qos.ratio(), BR_BALANCED);
+        assert_eq!(qos.latest_quality(), Quality::Balanced);
+    }
+
+    #[test]
+    fn test_latest_quality_clock_jump_back() {
+        let mut qos = with_fake_clock();
+        qos.on_connection_open(1);
+        qos.on_connection_open(2);
+        qos.user_image_quality(1, ImageQuality::Best.value());
+        // 时钟回拨后用户2的设置时间戳更小, 但仍是最新的设置
+        FAKE_NOW.with(|now| now.set(now.get() - 10_000));
+        qos.user_image_quality(2, ImageQuality::Low.value());
+        assert!(qos.users[&2].quality.unwrap().0 < qos.users[&1].quality.unwrap().0);
+        assert_eq!(qos.latest_quality(), Quality::Low);
+
+        FAKE_NOW.with(|now| now.set(now.get() - 10_000));
+        qos.user_image_quality(1, ImageQuality::Balanced.value());
+        assert_eq!(qos.latest_quality(), Quality::Balanced);
+    }
+
+    #[test]
+    fn test_max_displays_evicts_lru() {
+        let mut qos = with_fake_clock();
+        for i in 0..MAX_DISPLAYS {
+            qos.new_display(format!("display{}", i));
+            advance_fake_now(Duration::from_millis(10));
+        }
+        assert_eq!(qos.displays.len(), MAX_DISPLAYS);
+        // display0最早注册, 但最近有更新
+        qos.update_display_data("display0", 0);
+        advance_fake_now(Duration::from_millis(10));
+
+        qos.new_display("extra0".to_owned());
+        assert_eq!(qos.displays.len(), MAX_DISPLAYS);
+        assert!(qos.displays.contains_key("display0"));
+        assert!(!qos.displays.contains_key("display1"));
+        assert!(qos.displays.contains_key("extra0"));
+
+        // 重复注册已有显示不淘汰
+        qos.new_display("display2".to_owned());
+        assert!(qos.displays.contains_key("display3"));
+
+        for i in 0..MAX_DISPLAYS * 4 {
+            advance_fake_now(Duration::from_millis(10));
+            qos.new_display(format!("spam{}", i));
+        }
+        assert_eq!(qos.displays.len(), MAX_DISPLAYS);
+        assert!(qos.displays.keys().all(|name| name.starts_with("spam")));
+
+        // 通过设置自动添加的显示同样受数量限制, 并记录更新时间
+        advance_fake_now(Duration::from_millis(10));
+        qos.set_support_changing_quality("late", false);
+        assert_eq!(qos.displays.len(), MAX_DISPLAYS);
+        assert_eq!(qos.displays["late"].last_update_ms, fake_now());
+        assert!(!qos
+            .displays
+            .contains_key(&format!("spam{}", MAX_DISPLAYS * 3)));
+    }
+
+    #[test]
+    fn test_apply_remote_settings() {
+        let mut qos = VideoQoS::default();
+        qos.on_connection_open(1);
+        qos.apply_remote_settings(
+            1,
+            RemoteQosSettings {
+                fps: Some(90),
+                quality: Some(Quality::Low),
+                record: Some(true),
+            },
+        );
+        assert_eq!(qos.users[&1].fps, Some(90));
+        assert_eq!(qos.users[&1].quality.map(|(_, q)| q), Some(Quality::Low));
+        assert!(qos.record());
+        assert_eq!(qos.target_fps, 90);
+        // 录制最低画质在同一次协商中生效, 比例只改变一次
+        assert_eq!(qos.ratio(), Quality::Balanced.ratio());
+
+        qos.apply_remote_settings(
+            1,
+            RemoteQosSettings {
+                quality: Some(Quality::Best),
+                record: Some(false),
+                ..Default::default()
+            },
+        );
+        assert_eq!(qos.ratio(), BR_BEST);
+        assert_eq!(qos.users[&1].fps, Some(90));
+
+        // 空设置不改变任何状态
+        qos.apply_remote_settings(1, RemoteQosSettings::default());
+        assert_eq!(qos.ratio(), BR_BEST);
+        assert!(!qos.record());
+
+        // 无效的自定义比例回退到平衡画质
+        qos.apply_remote_settings(
+            1,
+            RemoteQosSettings {
+                quality: Some(Quality::Custom(f32::NAN)),
+                ..Default::default()
+            },
+        );
+        assert_eq!(qos.latest_quality(), Quality::Balanced);
+        assert_eq!(qos.ratio(), BR_BALANCED);
+    }
+
+    #[test]
+    fn test_fps_conflict() {
+        let mut qos = VideoQoS::default();
+        for id in 1..=3 {
+            qos.on_connection_open(id);
+        }
+        assert_eq!(qos.fps_conflict(), None);
+        qos.user_fps(1, 60);
+        assert_eq!(qos.fps_conflict(), None);
+        qos.user_fps(2, 60 + FPS_CONFLICT_THRESHOLD);
+        assert_eq!(qos.fps_conflict(), None);
+
+        qos.user_fps(3, 20);
+        qos.user_fps(2, 120);
+        assert_eq!(qos.fps_conflict(), Some((20, 120)));
+        // 协商的FPS不受影响
+        assert_eq!(qos.target_fps, qos.requested_fps().unwrap());
+        assert_eq!(qos.target_fps, MIN_FPS);
+
+        qos.on_connection_close(3);
+        assert_eq!(qos.fps_conflict(), Some((60, 120)));
+        qos.on_connection_close(2);
+        assert_eq!(qos.fps_conflict(), None);
+    }
+
+    #[test]
+    fn test_spf_for_display_backlog() {
+        let mut qos = with_fake_clock();
+        qos.new_display("display0".to_owned());
+        qos.new_display("display1".to_owned());
+        qos.set_fixed_fps(Some(100));
+        settle_fps(&mut qos);
+        assert_eq!(qos.spf_for_display("display0"), Duration::from_millis(10));
+        assert_eq!(qos.spf_for_display("display1"), Duration::from_millis(10));
+        assert_eq!(qos.spf_for_display("unknown"), qos.spf());
+
+        // 按FPS发送或画面静止时不延长
+        advance_fake_now(Duration::from_secs(1));
+        qos.update_display_data("display0", 100);
+        assert_eq!(qos.spf_for_display("display0"), Duration::from_millis(10));
+        advance_fake_now(Duration::from_secs(1));
+        qos.report_idle_frames("display0", 100);
+        qos.update_display_data("display0", 0);
+        assert_eq!(qos.spf_for_display("display0"), Duration::from_millis(10));
+
+        // 积压时只延长该显示的间隔
+        report_backlog(&mut qos, "display0", SEND_COUNTER_THRESHOLD);
+        assert_eq!(qos.spf_for_display("display0"), Duration::from_millis(10));
+        report_backlog(&mut qos, "display0", SEND_COUNTER_THRESHOLD * 2);
+        assert_eq!(qos.spf_for_display("display0"), Duration::from_millis(20));
+        report_backlog(&mut qos, "display0", SEND_COUNTER_THRESHOLD * 100);
+        assert_eq!(
+            qos.spf_for_display("display0"),
+            Duration::from_millis(10).mul_f32(BACKLOG_MAX_STRETCH)
+        );
+        assert_eq!(qos.spf_for_display("display1"), Duration::from_millis(10));
+
+        // 积压消失后恢复
+        report_backlog(&mut qos, "display0", 0);
+        assert_eq!(qos.spf_for_display("display0"), Duration::from_millis(10));
+
+        // 自动模式下编码较慢的显示使用自己的FPS, 不影响其他显示
+        qos.set_fixed_fps(None);
+        for _ in 0..ENCODE_TIME_WINDOW {
+            qos.report_encode_time("display1", Duration::from_millis(15));
+        }
+        let cap = qos.fps_for_display("display1");
+        assert!(cap < qos.fps());
+        assert_eq!(qos.spf_for_display("display1"), spf_from_fps(cap));
+        assert_eq!(qos.spf_for_display("display0"), Duration::from_millis(10));
+        assert!(qos.spf_for_display("display1") > qos.spf_for_display("display0"));
+    }
+
+    #[test]
+    fn test_warm_state() {
+        let mut qos = with_fake_clock();
+        qos.new_display("display0".to_owned());
+        qos.on_connection_open(1);
+        qos.user_image_quality(1, ImageQuality::Best.value());
+        qos.user_fps(1, 90);
+        settle_fps(&mut qos);
+        qos.on_connection_close(1);
+        assert_eq!(qos.ratio(), BR_BALANCED);
+        assert_eq!(qos.fps(), FPS);
+
+        // 很快重新连接时恢复离开时的状态
+        advance_fake_now(WARM_GRACE);
+        qos.on_connection_open(1);
+        assert_eq!(qos.ratio(), BR_BEST);
+        assert_eq!(qos.fps(), 90);
+        assert_eq!(qos.target_fps, 90);
+
+        // 超过保留时间后重新开始
+        qos.on_connection_close(1);
+        advance_fake_now(WARM_GRACE + Duration::from_millis(1));
+        qos.on_connection_open(1);
+        assert_eq!(qos.ratio(), BR_BALANCED);
+        assert_eq!(qos.fps(), FPS);
+    }
+
+    #[test]
+    fn test_stable_vbr_state() {
+        let mut qos = with_fake_clock();
+        assert!(!qos.stable_vbr_state());
+        // 第一个显示立即生效
+        qos.new_display("display0".to_owned());
+        assert!(qos.stable_vbr_state());
+
+        // 频繁切换时保持原来的状态
+        for _ in 0..10 {
+            qos.set_support_changing_quality("display1", false);
+            assert!(!qos.in_vbr_state());
+            assert!(qos.stable_vbr_state());
+            advance_fake_now(VBR_DEBOUNCE / 4);
+            qos.remove_display("display1");
+            assert!(qos.stable_vbr_state());
+            advance_fake_now(VBR_DEBOUNCE / 4);
+        }
+
+        // 稳定足够久后切换
+        qos.set_support_changing_quality("display1", false);
+        advance_fake_now(VBR_DEBOUNCE - Duration::from_millis(1));
+        assert!(qos.stable_vbr_state());
+        advance_fake_now(Duration::from_millis(1));
+        assert!(!qos.stable_vbr_state());
+
+        // 恢复也需要保持足够久
+        qos.remove_display("display1");
+        assert!(qos.in_vbr_state());
+        assert!(!qos.stable_vbr_state());
+        advance_fake_now(VBR_DEBOUNCE);
+        assert!(qos.stable_vbr_state());
+
+        // 移除最后一个显示后立即不启用
+        qos.set_support_changing_quality("display1", false);
+        qos.remove_display("display0");
+        qos.remove_display("display1");
+        assert!(!qos.stable_vbr_state());
+        qos.new_display("display0".to_owned());
+        assert!(qos.stable_vbr_state());
+    }
+
+    #[test]
+    fn test_store_bitrate_clamped() {
+        let mut qos = VideoQoS::default();
+        for _ in 0..BITRATE_WINDOW * 2 {
+            qos.store_bitrate(u32::MAX);
+        }
+        assert!(qos.bitrate_clamp_logged);
+        assert_eq!(qos.bitrate(), MAX_BITRATE);
+        assert_eq!(qos.average_bitrate(), MAX_BITRATE);
+
+        qos.store_bitrate(1000);
+        assert!(qos.average_bitrate() <= MAX_BITRATE);
+    }
+
+    #[test]
+    fn test_leave_fixed_fps_keeps_current() {
+        let mut qos = VideoQoS::default();
+        qos.update_network_rtt(Duration::from_millis(1));
+        qos.update_network_rtt(Duration::from_millis(1));
+        let adaptive = qos.fps();
+        assert_ne!(adaptive, FPS);
+
+        qos.set_fixed_fps(Some(MAX_FPS));
+        qos.set_fixed_fps(None);
+        settle_fps(&mut qos);
+        assert_eq!(qos.fps(), adaptive);
+        assert_eq!(qos.target_fps, adaptive);
+
+        // 固定FPS生效后退出时从固定FPS开始
+        qos.set_fixed_fps(Some(100));
+        settle_fps(&mut qos);
+        qos.set_fixed_fps(None);
+        settle_fps(&mut qos);
+        assert_eq!(qos.fps(), 100);
+
+        // 有用户请求时使用请求的FPS
+        qos.set_fixed_fps(Some(MAX_FPS));
+        qos.on_connection_open(1);
+        qos.user_fps(1, 70);
+        qos.set_fixed_fps(None);
+        assert_eq!(qos.target_fps, 70);
+    }
+}
@@ -494,7 +494,23 @@ fn run(vs: VideoService) -> ResultType<()> {
         c.set_gdi();
     }
     let mut video_qos = VIDEO_QOS.lock().unwrap();
-    let mut spf = video_qos.spf();
+    video_qos.set_quality_stale_timeout(
+        Config::get_option("video-quality-stale-timeout")
+            .parse()
+            .ok()
+            .map(Duration::from_secs),
+    );
+    video_qos.set_rtt_band(
+        Config::get_option("video-rtt-low-ms")
+            .parse()
+            .ok()
+            .map(Duration::from_millis),
+        Config::get_option("video-rtt-high-ms")
+            .parse()
+            .ok()
+            .map(Duration::from_millis),
+    );
+    let mut spf = video_qos.spf_for_display(&sp.name());
     let mut quality = video_qos.ratio();
     let record_incoming = config::option2bool(
         "allow-auto-record-incoming",
@@ -548,6 +564,10 @@ fn run(vs: VideoService) -> ResultType<()> {
         .lock()
         .unwrap()
         .set_support_changing_quality(&sp.name(), encoder.support_changing_quality());
+    VIDEO_QOS
+        .lock()
+        .unwrap()
+        .set_display_resolution(&sp.name(), c.width as _, c.height as _);
     log::info!("initial quality: {quality:?}");
 
     if sp.is_option_true(OPTION_REFRESH) {
@@ -575,7 +595,7 @@ fn run(vs: VideoService) -> ResultType<()> {
     let mut first_frame = true;
     let capture_width = c.width;
     let capture_height = c.height;
-    let (mut second_instant, mut send_counter) = (Instant::now(), 0);
+    let (mut second_instant, mut send_counter, mut idle_counter) = (Instant::now(), 0, 0);
 
     while sp.ok() {
         #[cfg(windows)]
@@ -586,6 +606,7 @@ fn run(vs: VideoService) -> ResultType<()> {
             &mut spf,
             client_record,
             &mut send_counter,
+            &mut idle_counter,
             &mut second_instant,
             &sp.name(),
         )?;
@@ -639,6 +660,7 @@ fn run(vs: VideoService) -> ResultType<()> {
         }
 
         frame_controller.reset();
+        let send_counter_before = send_counter;
 
         let time = now - start;
         let ms = (time.as_secs() * 1000 + time.subsec_millis() as u64) as i64;
@@ -788,6 +810,10 @@ fn run(vs: VideoService) -> ResultType<()> {
                 }
             }
         }
+        // No new frame was sent in this slot, so it's not counted as backlog.
+        if send_counter == send_counter_before {
+            idle_counter += 1;
+        }
 
         let mut fetched_conn_ids = HashSet::new();
         let timeout_millis = 3_000u64;
@@ -1071,8 +1097,13 @@ fn handle_one_frame(
     let mut send_conn_ids: HashSet<i32> = Default::default();
     let first = *first_frame;
     *first_frame = false;
+    let encode_start = Instant::now();
     match encoder.encode_to_message(frame, ms) {
         Ok(mut vf) => {
+            VIDEO_QOS
+                .lock()
+                .unwrap()
+                .report_encode_time(&sp.name(), encode_start.elapsed());
             *encode_fail_counter = 0;
             vf.display = display as _;
             let mut msg = Message::new();
@@ -1234,19 +1265,29 @@ fn check_qos(
     spf: &mut Duration,
     client_record: bool,
     send_counter: &mut usize,
+    idle_counter: &mut usize,
     second_instant: &mut Instant,
     name: &str,
 ) -> ResultType<()> {
     let mut video_qos = VIDEO_QOS.lock().unwrap();
-    *spf = video_qos.spf();
+    video_qos.tick(Instant::now());
+    *spf = video_qos.spf_for_display(name);
     if *ratio != video_qos.ratio() {
-        *ratio = video_qos.ratio();
         if encoder.support_changing_quality() {
+            *ratio = video_qos.ratio();
             allow_err!(encoder.set_quality(*ratio));
             video_qos.store_bitrate(encoder.bitrate());
+            log::debug!(
+                "ratio changed to {}, bitrate {} kbps, average {} kbps",
+                *ratio,
+                video_qos.bitrate(),
+                video_qos.average_bitrate()
+            );
         } else {
-            // Now only vaapi doesn't support changing quality
-            if !video_qos.in_vbr_state() && !video_qos.latest_quality().is_custom() {
+            // Now only vaapi doesn't support changing quality.
+            // Keep the old ratio until the switch, so a change made while the VBR state
+            // is still debouncing is retried instead of lost.
+            if !video_qos.stable_vbr_state() && !video_qos.latest_quality().is_custom() {
                 log::info!("switch to change quality");
                 bail!("SWITCH");
             }
@@ -1258,8 +1299,10 @@ fn check_qos(
     }
     if second_instant.elapsed() > Duration::from_secs(1) {
         *second_instant = Instant::now();
+        video_qos.report_idle_frames(&name, *idle_counter);
         video_qos.update_display_data(&name, *send_counter);
         *send_counter = 0;
+        *idle_counter = 0;
     }
     drop(video_qos);
     Ok(())